@@ -1,84 +1,551 @@
-use clap::Parser;
-use rand::Rng;
+use async_trait::async_trait;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use clap::{Parser, ValueEnum};
+use futures_util::sink::Sink;
+use futures_util::stream::Stream as WsStream;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::collections::{HashMap, VecDeque};
 use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf, ReadHalf, WriteHalf,
+};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{Duration, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+use zeroize::Zeroize;
 
-const P: u64 = 0xD87FA3E291B4C7F3;
-const G: u64 = 2;
+const HKDF_INFO: &[u8] = b"rust-chat v1";
 
-fn mod_pow(mut base: u64, mut exp: u64, modu: u64) -> u64 {
-    if modu == 1 {
-        return 0;
+/// Upper bound on a frame's body (nonce + ciphertext). The length prefix
+/// isn't authenticated until the tag check at the end of `recv_frame`, so
+/// without this cap a corrupt or malicious length would force allocating
+/// up to 4 GiB per frame before that check ever runs.
+const MAX_FRAME_BODY_LEN: usize = 1 << 20;
+
+/// Rekey after this many messages sent in one direction, whichever comes
+/// first alongside [`REKEY_AFTER_TIME`].
+const REKEY_AFTER_MESSAGES: u64 = 50;
+
+/// Also rekey on a fixed interval of this much wall-clock time, counted from
+/// the start of the session; a rekey triggered by [`REKEY_AFTER_MESSAGES`] or
+/// by the peer does not restart this interval.
+const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+
+/// Any duplex byte stream the chat loop can run over. Implemented for plain
+/// TCP streams and, via [`WsDuplex`], for WebSocket connections.
+trait AsyncDuplex: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncDuplex for T {}
+
+/// A boxed connection, independent of which [`AsyncTransport`] produced it.
+type BoxedDuplex = Box<dyn AsyncDuplex>;
+
+/// One side of a listening socket, abstracted over the carrier (TCP,
+/// WebSocket, ...). `accept` only does the cheap, trusted step (the OS-level
+/// TCP accept); anything that involves talking to the untrusted peer (e.g.
+/// the WebSocket upgrade) is deferred to [`PendingConn::finish`], which the
+/// caller runs off the accept loop so one slow or malformed peer can't stall
+/// or fail every other connection.
+#[async_trait]
+trait TransportListener: Send {
+    async fn accept(&mut self) -> io::Result<PendingConn>;
+}
+
+/// A freshly-accepted, not-yet-usable connection plus its peer address.
+enum PendingConn {
+    Tcp(TcpStream, String),
+    Ws(TcpStream, String),
+}
+
+impl PendingConn {
+    /// Run whatever carrier-specific handshake is still needed (none, for
+    /// plain TCP) and produce a ready-to-use duplex stream.
+    async fn finish(self) -> io::Result<(BoxedDuplex, String)> {
+        match self {
+            PendingConn::Tcp(stream, addr) => Ok((Box::new(stream), addr)),
+            PendingConn::Ws(stream, addr) => {
+                let ws = tokio_tungstenite::accept_async(stream).await.map_err(ws_err)?;
+                Ok((Box::new(WsDuplex::new(ws)), addr))
+            }
+        }
     }
-    let mut result = 1;
-    base %= modu;
-    while exp > 0 {
-        if exp % 2 == 1 {
-            result = (result * base) % modu;
+}
+
+/// A carrier the encrypted chat can run over. The handshake and chat loop
+/// are written against this trait, not against `TcpStream`. Adding a new
+/// carrier (TLS, Unix sockets, ...) means an impl of this trait and of
+/// [`TransportListener`], plus a `PendingConn` variant and a `TransportKind`
+/// entry; the crypto code is untouched.
+#[async_trait]
+trait AsyncTransport: Send + Sync {
+    async fn connect(&self, addr: &str) -> io::Result<BoxedDuplex>;
+    async fn bind(&self, addr: &str) -> io::Result<Box<dyn TransportListener>>;
+}
+
+struct TcpTransport;
+
+struct TcpTransportListener(TcpListener);
+
+#[async_trait]
+impl TransportListener for TcpTransportListener {
+    async fn accept(&mut self) -> io::Result<PendingConn> {
+        let (stream, addr) = self.0.accept().await?;
+        Ok(PendingConn::Tcp(stream, addr.to_string()))
+    }
+}
+
+#[async_trait]
+impl AsyncTransport for TcpTransport {
+    async fn connect(&self, addr: &str) -> io::Result<BoxedDuplex> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(Box::new(stream))
+    }
+
+    async fn bind(&self, addr: &str) -> io::Result<Box<dyn TransportListener>> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Box::new(TcpTransportListener(listener)))
+    }
+}
+
+fn ws_err(e: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Adapts a WebSocket's message stream/sink into a plain `AsyncRead` +
+/// `AsyncWrite` byte stream, so the AEAD framing above rides on top
+/// unchanged: each `poll_write` call sends one binary message, and
+/// `poll_read` drains incoming binary messages into a byte buffer
+/// regardless of how they were chunked on the wire.
+struct WsDuplex<S> {
+    inner: WebSocketStream<S>,
+    read_buf: VecDeque<u8>,
+}
+
+impl<S> WsDuplex<S> {
+    fn new(inner: WebSocketStream<S>) -> Self {
+        WsDuplex {
+            inner,
+            read_buf: VecDeque::new(),
         }
-        exp >>= 1;
-        base = (base * base) % modu;
     }
-    result
 }
 
-struct DHKeys {
-    private: u64,
-    public: u64,
+impl<S> AsyncRead for WsDuplex<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if !self.read_buf.is_empty() {
+                let n = buf.remaining().min(self.read_buf.len());
+                let chunk: Vec<u8> = self.read_buf.drain(..n).collect();
+                buf.put_slice(&chunk);
+                return Poll::Ready(Ok(()));
+            }
+
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(Message::Binary(data)))) => {
+                    self.read_buf.extend(data);
+                }
+                Poll::Ready(Some(Ok(_))) => continue,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(ws_err(e))),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WsDuplex<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.inner).poll_ready(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(ws_err(e))),
+            Poll::Pending => return Poll::Pending,
+        }
+        if let Err(e) = Pin::new(&mut self.inner).start_send(Message::Binary(buf.to_vec())) {
+            return Poll::Ready(Err(ws_err(e)));
+        }
+        // Our callers drive this through `write_all`, which never calls
+        // `flush` on its own, so kick the send off here instead of letting it
+        // sit in the sink's internal buffer until some later write happens to
+        // flush it. `poll_ready` on the next call drains any of this that
+        // didn't make it out yet, so a `Pending` here can't cause a double
+        // send.
+        match Pin::new(&mut self.inner).poll_flush(cx) {
+            Poll::Ready(Ok(())) | Poll::Pending => Poll::Ready(Ok(buf.len())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(ws_err(e))),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(ws_err)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(ws_err)
+    }
+}
+
+struct WebSocketTransport;
+
+struct WsListener(TcpListener);
+
+#[async_trait]
+impl TransportListener for WsListener {
+    async fn accept(&mut self) -> io::Result<PendingConn> {
+        let (tcp, addr) = self.0.accept().await?;
+        Ok(PendingConn::Ws(tcp, addr.to_string()))
+    }
 }
 
-impl DHKeys {
-    fn new() -> Self {
-        let private = rand::thread_rng().
-        let public = mod_pow(G, private, P);
-        DHKeys { private, public }
+#[async_trait]
+impl AsyncTransport for WebSocketTransport {
+    async fn connect(&self, addr: &str) -> io::Result<BoxedDuplex> {
+        let url = format!("ws://{}", addr);
+        let (ws, _) = tokio_tungstenite::connect_async(url).await.map_err(ws_err)?;
+        Ok(Box::new(WsDuplex::new(ws)))
+    }
+
+    async fn bind(&self, addr: &str) -> io::Result<Box<dyn TransportListener>> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Box::new(WsListener(listener)))
     }
 }
 
-fn compute_shared_secret(their_public: u64, our_private: u64) -> u64 {
-    mod_pow(their_public, our_private, P)
+#[derive(ValueEnum, Clone, Debug)]
+enum TransportKind {
+    Tcp,
+    Ws,
+}
+
+impl TransportKind {
+    fn build(&self) -> Box<dyn AsyncTransport> {
+        match self {
+            TransportKind::Tcp => Box::new(TcpTransport),
+            TransportKind::Ws => Box::new(WebSocketTransport),
+        }
+    }
 }
 
-struct LCG {
-    a: u64,
-    c: u64,
-    m: u64,
-    state: u64,
+/// One AEAD cipher plus the per-direction nonce counter that backs it.
+///
+/// The counter is incremented for every frame and must never repeat under
+/// the same key, so each direction (send / receive) gets its own instance.
+/// `ChaCha20Poly1305` zeroizes its own expanded key schedule on drop, and the
+/// raw `key` copy kept here is wiped in our own `Drop` impl below, so a rekey
+/// swapping this out for a fresh instance doesn't leave the old key material
+/// behind in either place.
+struct DirectionalCipher {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    key: [u8; 32],
 }
 
-impl LCG {
-    fn new(seed: u64) -> Self {
-        LCG {
-            a: 1103515245,
-            c: 12345,
-            m: 1u64 << 32,
-            state: seed,
+impl DirectionalCipher {
+    fn new(key: [u8; 32]) -> Self {
+        DirectionalCipher {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            counter: 0,
+            key,
         }
     }
 
-    fn next(&mut self) -> u8 {
-        self.state = (self.a * self.state + self.c) % self.m;
-        (self.state >> 24) as u8
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        nonce
     }
 }
 
-fn xor_crypt(data: &[u8], keystream: &mut LCG) -> Vec<u8> {
-    data.iter().map(|&b| b ^ keystream.next()).collect()
+impl Drop for DirectionalCipher {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// The two directional ciphers produced by a handshake: one to encrypt
+/// outgoing frames, one to decrypt incoming ones.
+struct SessionCrypto {
+    send: DirectionalCipher,
+    recv: DirectionalCipher,
+}
+
+/// Expand a freshly-computed X25519 shared point into the two directional
+/// session keys via HKDF-SHA256, returning `(send_key, recv_key)` from the
+/// point of view of `is_server`.
+fn derive_directional_keys(shared_secret: &[u8], is_server: bool) -> io::Result<([u8; 32], [u8; 32])> {
+    let hk = Hkdf::<Sha256>::new(None, shared_secret);
+    let mut okm = [0u8; 64];
+    hk.expand(HKDF_INFO, &mut okm)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "HKDF expand failed"))?;
+    let client_to_server: [u8; 32] = okm[..32].try_into().unwrap();
+    let server_to_client: [u8; 32] = okm[32..].try_into().unwrap();
+    okm.zeroize();
+
+    Ok(if is_server {
+        (server_to_client, client_to_server)
+    } else {
+        (client_to_server, server_to_client)
+    })
+}
+
+/// Run the X25519 + HKDF-SHA256 handshake over `stream` and derive the
+/// directional session keys.
+///
+/// Both sides generate an ephemeral keypair, exchange the 32-byte public
+/// keys, and compute the shared point. HKDF-SHA256 then expands that shared
+/// point into two 32-byte keys, one per direction, so the client->server and
+/// server->client streams never reuse a keystream.
+async fn handshake(stream: &mut BoxedDuplex, is_server: bool) -> io::Result<SessionCrypto> {
+    let our_secret = EphemeralSecret::random();
+    let our_public = PublicKey::from(&our_secret);
+
+    let their_public = if is_server {
+        stream.write_all(our_public.as_bytes()).await?;
+        let mut buf = [0u8; 32];
+        stream.read_exact(&mut buf).await?;
+        PublicKey::from(buf)
+    } else {
+        let mut buf = [0u8; 32];
+        stream.read_exact(&mut buf).await?;
+        stream.write_all(our_public.as_bytes()).await?;
+        PublicKey::from(buf)
+    };
+
+    let shared_secret = our_secret.diffie_hellman(&their_public);
+    let (send_key, recv_key) = derive_directional_keys(shared_secret.as_bytes(), is_server)?;
+
+    Ok(SessionCrypto {
+        send: DirectionalCipher::new(send_key),
+        recv: DirectionalCipher::new(recv_key),
+    })
+}
+
+/// A frame's type byte, prepended to its length header. `Data` carries a
+/// chat message; `Rekey` carries a fresh ephemeral X25519 public key as its
+/// plaintext payload; `Close` announces a clean shutdown and carries no
+/// payload. All three are still AEAD-encrypted under the current direction
+/// key, with the type byte bound in as associated data and the nonce checked
+/// against the receive counter, so a rekey can't be forged or replayed by an
+/// on-path attacker.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum FrameType {
+    Data,
+    Rekey,
+    Close,
+}
+
+impl FrameType {
+    fn to_byte(self) -> u8 {
+        match self {
+            FrameType::Data => 0,
+            FrameType::Rekey => 1,
+            FrameType::Close => 2,
+        }
+    }
+
+    fn from_byte(b: u8) -> io::Result<Self> {
+        match b {
+            0 => Ok(FrameType::Data),
+            1 => Ok(FrameType::Rekey),
+            2 => Ok(FrameType::Close),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown frame type")),
+        }
+    }
+}
+
+/// Encrypt `plaintext` and write it as a framed message: a 1-byte frame
+/// type, a 4-byte big-endian length, then the 12-byte nonce and the AEAD
+/// ciphertext (including its 16-byte tag).
+async fn send_frame(
+    stream: &mut WriteHalf<BoxedDuplex>,
+    cipher: &mut DirectionalCipher,
+    frame_type: FrameType,
+    plaintext: &[u8],
+) -> io::Result<()> {
+    let nonce_bytes = cipher.next_nonce();
+    let nonce = Nonce::from_slice(&nonce_bytes);
+    let aad = [frame_type.to_byte()];
+    let ciphertext = cipher
+        .cipher
+        .encrypt(nonce, Payload { msg: plaintext, aad: &aad })
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?;
+
+    let body_len = (nonce_bytes.len() + ciphertext.len()) as u32;
+    stream.write_all(&[frame_type.to_byte()]).await?;
+    stream.write_all(&body_len.to_be_bytes()).await?;
+    stream.write_all(&nonce_bytes).await?;
+    stream.write_all(&ciphertext).await?;
+    Ok(())
+}
+
+/// Read one framed message and decrypt it. A failed AEAD tag is returned as
+/// an error so the caller closes the connection instead of printing garbage.
+async fn recv_frame(
+    stream: &mut ReadHalf<BoxedDuplex>,
+    cipher: &mut DirectionalCipher,
+) -> io::Result<(FrameType, Vec<u8>)> {
+    let mut type_buf = [0u8; 1];
+    stream.read_exact(&mut type_buf).await?;
+    let frame_type = FrameType::from_byte(type_buf[0])?;
+
+    let mut len_buf = [0u8; 4];
+    stream.read_exact(&mut len_buf).await?;
+    let body_len = u32::from_be_bytes(len_buf) as usize;
+    if body_len < 12 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too short"));
+    }
+    if body_len > MAX_FRAME_BODY_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+    }
+
+    let mut body = vec![0u8; body_len];
+    stream.read_exact(&mut body).await?;
+    let (nonce_bytes, ciphertext) = body.split_at(12);
+    if nonce_bytes != &cipher.next_nonce()[..] {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected nonce"));
+    }
+    let nonce = Nonce::from_slice(nonce_bytes);
+
+    let aad = [frame_type.to_byte()];
+    let plaintext = cipher
+        .cipher
+        .decrypt(nonce, Payload { msg: ciphertext, aad: &aad })
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "authentication failed"))?;
+    Ok((frame_type, plaintext))
+}
+
+/// Instruction from the reader side of a rekey exchange to the writer side,
+/// which is the only one allowed to touch the write half of the connection.
+enum WriteCmd {
+    /// Announce our fresh public key under the current send key. The send
+    /// cipher does *not* switch yet: we don't know the new key ourselves
+    /// until the peer's half arrives, and the writer must stop sending Data
+    /// frames in the meantime or the peer (which installs its new receive
+    /// key as soon as it sees this) would fail to decrypt them. See
+    /// `RekeyState`.
+    SendRekey([u8; 32]),
+    /// A peer-initiated rekey: send `our_new_public` back under the current
+    /// send key, then switch to `new_send_key`. Both halves of the exchange
+    /// are already known at this point, so there's no window to quiesce.
+    RespondAndSwap {
+        our_new_public: [u8; 32],
+        new_send_key: [u8; 32],
+    },
+    /// Our own previously-sent `SendRekey` is now answered: switch to
+    /// `new_send_key` and resume sending Data frames.
+    SwapSendKey([u8; 32]),
+}
+
+/// Where a connection's rekey exchange currently stands. Owned exclusively
+/// by the reader task (see `run_chat_session`/`handle_client`), so the
+/// initiate-vs-respond decision and the eventual key switch are made in one
+/// place with no cross-task race between "we just initiated" and "a frame
+/// just arrived" - both are just events in the same `select!` loop.
+enum RekeyState {
+    Idle,
+    /// We sent a `Rekey` frame with our public key and are waiting for the
+    /// peer's half before we can derive anything.
+    Initiated(EphemeralSecret),
+}
+
+/// Generate a fresh ephemeral keypair, mark the rekey as initiated, and tell
+/// the writer to announce it. Does nothing if a rekey is already in flight -
+/// only one is allowed outstanding at a time.
+async fn start_rekey(state: &mut RekeyState, cmd_tx: &mpsc::Sender<WriteCmd>) -> io::Result<()> {
+    if matches!(state, RekeyState::Initiated(_)) {
+        return Ok(());
+    }
+    let secret = EphemeralSecret::random();
+    let public = PublicKey::from(&secret);
+    *state = RekeyState::Initiated(secret);
+    cmd_tx
+        .send(WriteCmd::SendRekey(*public.as_bytes()))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "writer gone"))
+}
+
+/// Handle a `Rekey` frame's payload (the peer's fresh public key).
+///
+/// If `state` is `Initiated`, this is the other half of an exchange we
+/// started: pair our pending secret with the peer's key and tell the writer
+/// to switch its send key. This also covers the case where both sides
+/// initiated at once (crossed rekeys) - X25519 is symmetric, so pairing our
+/// own pending secret with whatever public key just arrived yields the same
+/// shared point the peer computes by pairing its pending secret with ours,
+/// regardless of which frame either side treats as the "reply".
+///
+/// If `state` is `Idle`, the peer initiated unprompted: generate our own
+/// ephemeral key, install the new receive key immediately (safe here because
+/// we're about to answer in the same frame, so the peer can't yet be
+/// expecting our new send key), and ask the writer to send our half of the
+/// reply before switching its own send key.
+async fn handle_rekey_frame(
+    body: &[u8],
+    state: &mut RekeyState,
+    recv_cipher: &mut DirectionalCipher,
+    is_server: bool,
+    cmd_tx: &mpsc::Sender<WriteCmd>,
+) -> io::Result<()> {
+    if body.len() != 32 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed rekey frame"));
+    }
+    let peer_public = PublicKey::from(<[u8; 32]>::try_from(body).unwrap());
+
+    let cmd = match std::mem::replace(state, RekeyState::Idle) {
+        RekeyState::Initiated(our_secret) => {
+            let shared = our_secret.diffie_hellman(&peer_public);
+            let (send_key, recv_key) = derive_directional_keys(shared.as_bytes(), is_server)?;
+            *recv_cipher = DirectionalCipher::new(recv_key);
+            WriteCmd::SwapSendKey(send_key)
+        }
+        RekeyState::Idle => {
+            let our_secret = EphemeralSecret::random();
+            let our_public = PublicKey::from(&our_secret);
+            let shared = our_secret.diffie_hellman(&peer_public);
+            let (send_key, recv_key) = derive_directional_keys(shared.as_bytes(), is_server)?;
+            *recv_cipher = DirectionalCipher::new(recv_key);
+            WriteCmd::RespondAndSwap {
+                our_new_public: *our_public.as_bytes(),
+                new_send_key: send_key,
+            }
+        }
+    };
+
+    cmd_tx
+        .send(cmd)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "writer gone"))
 }
 
 #[derive(Parser, Debug)]
-#[clap(about = "Encrypted chat with Diffie-Hellman and stream cipher")]
+#[clap(about = "Encrypted chat with X25519 + ChaCha20-Poly1305")]
 enum Command {
     #[clap(name = "server")]
     Server {
         #[clap(short, long, default_value = "8080")]
         port: u16,
+        #[clap(short, long, value_enum, default_value = "tcp")]
+        transport: TransportKind,
     },
     #[clap(name = "client")]
     Client {
         #[clap(short, long)]
         addr: String,
+        #[clap(short, long, value_enum, default_value = "tcp")]
+        transport: TransportKind,
     },
 }
 
@@ -88,99 +555,325 @@ struct Args {
     command: Command,
 }
 
-async fn run_server(port: u16) -> io::Result<()> {
-    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
-    println!("[SERVER] Listening on 0.0.0.0:{}", port);
+/// Drive a handshaked connection full-duplex: one task decrypts and prints
+/// whatever arrives, another reads stdin and sends encrypted frames. Each
+/// task owns its own half of the connection and its own directional cipher.
+/// The reader owns the rekey state machine (see `RekeyState`) and tells the
+/// writer what to do via `cmd_tx`; the writer reports each Data frame it
+/// sends back via `sent_tx` so the reader can time rekeys by message count.
+/// The session ends as soon as either side hits EOF or an error.
+async fn run_chat_session(
+    stream: BoxedDuplex,
+    crypto: SessionCrypto,
+    is_server: bool,
+    peer_label: &'static str,
+) -> io::Result<()> {
+    let (mut read_half, mut write_half) = tokio::io::split(stream);
+    let mut send_cipher = crypto.send;
+    let mut recv_cipher = crypto.recv;
 
-    let (mut stream, addr) = listener.accept().await?;
-    println!("[SERVER] Client connected from {}", addr);
+    let (cmd_tx, mut cmd_rx) = mpsc::channel::<WriteCmd>(1);
+    let (sent_tx, mut sent_rx) = mpsc::unbounded_channel::<()>();
 
-    let our_keys = DHKeys::new();
-    stream.write_all(&our_keys.public.to_be_bytes()).await?;
+    let mut read_task = tokio::spawn(async move {
+        let mut rekey_state = RekeyState::Idle;
+        let mut messages_since_rekey: u64 = 0;
+        let mut rekey_timer = tokio::time::interval(REKEY_AFTER_TIME);
+        rekey_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        rekey_timer.tick().await; // first tick fires immediately
 
-    let mut their_public_buf = [0u8; 8];
-    stream.read_exact(&mut their_public_buf).await?;
-    let their_public = u64::from_be_bytes(their_public_buf);
+        loop {
+            tokio::select! {
+                _ = sent_rx.recv() => {
+                    messages_since_rekey += 1;
+                    if messages_since_rekey >= REKEY_AFTER_MESSAGES {
+                        messages_since_rekey = 0;
+                        if start_rekey(&mut rekey_state, &cmd_tx).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                _ = rekey_timer.tick() => {
+                    messages_since_rekey = 0;
+                    if start_rekey(&mut rekey_state, &cmd_tx).await.is_err() {
+                        break;
+                    }
+                }
+                frame = recv_frame(&mut read_half, &mut recv_cipher) => {
+                    match frame {
+                        Ok((FrameType::Data, plaintext)) => {
+                            println!("[{}] {}", peer_label, String::from_utf8_lossy(&plaintext));
+                        }
+                        Ok((FrameType::Rekey, body)) => {
+                            if handle_rekey_frame(&body, &mut rekey_state, &mut recv_cipher, is_server, &cmd_tx)
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
+                        }
+                        Ok((FrameType::Close, _)) => {
+                            println!("[SESSION] {} closed the session", peer_label);
+                            break;
+                        }
+                        Err(e) => {
+                            eprintln!("[SESSION] connection closed ({})", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    });
 
-    let shared_secret = compute_shared_secret(their_public, our_keys.private);
-    let mut lcg = LCG::new(shared_secret);
+    let mut write_task = tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        // Set once we've sent our own `Rekey` frame and are waiting on the
+        // peer's half; Data sends are paused until then, since the peer
+        // installs its new receive key as soon as it sees ours and would
+        // fail to decrypt anything still sent under the old one.
+        let mut rekey_in_flight = false;
 
-    let (mut reader, mut writer) = stream.split();
-    let mut input = String::new();
-    loop {
         print!("[YOU] ");
-        io::stdout().flush()?;
-        input.clear();
-        io::stdin().read_line(&mut input)?;
-        let input = input.trim();
-        if input.is_empty() {
-            continue;
+        let _ = io::stdout().flush();
+        loop {
+            tokio::select! {
+                cmd = cmd_rx.recv() => {
+                    let ok = match cmd {
+                        Some(WriteCmd::SendRekey(public)) => {
+                            let sent = send_frame(&mut write_half, &mut send_cipher, FrameType::Rekey, &public).await;
+                            rekey_in_flight = sent.is_ok();
+                            sent.is_ok()
+                        }
+                        Some(WriteCmd::RespondAndSwap { our_new_public, new_send_key }) => {
+                            let sent = send_frame(&mut write_half, &mut send_cipher, FrameType::Rekey, &our_new_public).await;
+                            send_cipher = DirectionalCipher::new(new_send_key);
+                            sent.is_ok()
+                        }
+                        Some(WriteCmd::SwapSendKey(new_send_key)) => {
+                            send_cipher = DirectionalCipher::new(new_send_key);
+                            rekey_in_flight = false;
+                            true
+                        }
+                        None => true,
+                    };
+                    if !ok {
+                        break;
+                    }
+                }
+                line = lines.next_line(), if !rekey_in_flight => {
+                    match line {
+                        Ok(Some(text)) => {
+                            let trimmed = text.trim();
+                            if !trimmed.is_empty() {
+                                if send_frame(&mut write_half, &mut send_cipher, FrameType::Data, trimmed.as_bytes())
+                                    .await
+                                    .is_err()
+                                {
+                                    break;
+                                }
+                                let _ = sent_tx.send(());
+                            }
+                            print!("[YOU] ");
+                            let _ = io::stdout().flush();
+                        }
+                        Ok(None) | Err(_) => {
+                            let _ = send_frame(&mut write_half, &mut send_cipher, FrameType::Close, &[]).await;
+                            break;
+                        }
+                    }
+                }
+            }
         }
+    });
 
-        let ciphertext = xor_crypt(input.as_bytes(), &mut lcg);
-        writer.write_all(&(ciphertext.len() as u32).to_be_bytes()).await?;
-        writer.write_all(&ciphertext).await?;
-
-        let mut len_buf = [0u8; 4];
-        reader.read_exact(&mut len_buf).await?;
-        let len = u32::from_be_bytes(len_buf) as usize;
-        let mut ciphertext = vec![0u8; len];
-        reader.read_exact(&mut ciphertext).await?;
-
-        let plaintext = xor_crypt(&ciphertext, &mut lcg);
-        let plaintext_str = String::from_utf8_lossy(&plaintext);
-        println!("[CLIENT] {}", plaintext_str);
+    tokio::select! {
+        _ = &mut read_task => write_task.abort(),
+        _ = &mut write_task => read_task.abort(),
     }
+
+    Ok(())
 }
 
-async fn run_client(addr: String) -> io::Result<()> {
-    let mut stream = TcpStream::connect(addr).await?;
-    println!("[CLIENT] Connected to server");
+/// Registry of connected clients: each entry is a channel feeding that
+/// client's own writer task, which encrypts under that client's own
+/// directional send key before putting bytes on the wire.
+type Roster = Arc<Mutex<HashMap<String, mpsc::Sender<Vec<u8>>>>>;
 
-    let our_keys = DHKeys::new();
+/// Handshake with one client, register it in the roster, and relay
+/// messages: frames received from this client are decrypted, then
+/// re-encrypted and fanned out to every other roster entry under each
+/// peer's own key. A peer whose queue is full does not get that message.
+async fn handle_client(mut stream: BoxedDuplex, addr: String, roster: Roster) -> io::Result<()> {
+    let crypto = handshake(&mut stream, true).await?;
+    let (mut read_half, mut write_half) = tokio::io::split(stream);
+    let mut send_cipher = crypto.send;
+    let mut recv_cipher = crypto.recv;
 
-    let mut their_public_buf = [0u8; 8];
-    stream.read_exact(&mut their_public_buf).await?;
-    let their_public = u64::from_be_bytes(their_public_buf);
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(32);
+    roster.lock().await.insert(addr.clone(), tx);
 
-    stream.write_all(&our_keys.public.to_be_bytes()).await?;
+    let (cmd_tx, mut cmd_rx) = mpsc::channel::<WriteCmd>(1);
+    let (sent_tx, mut sent_rx) = mpsc::unbounded_channel::<()>();
 
-    let shared_secret = compute_shared_secret(their_public, our_keys.private);
-    let mut lcg = LCG::new(shared_secret);
+    let write_task = tokio::spawn(async move {
+        // Mirrors the writer half in `run_chat_session`: pause relaying Data
+        // while our own rekey is in flight, see that function's comment.
+        let mut rekey_in_flight = false;
 
-    let (mut reader, mut writer) = stream.split();
-    let mut input = String::new();
-    loop {
-        let mut len_buf = [0u8; 4];
-        reader.read_exact(&mut len_buf).await?;
-        let len = u32::from_be_bytes(len_buf) as usize;
-        let mut ciphertext = vec![0u8; len];
-        reader.read_exact(&mut ciphertext).await?;
+        loop {
+            tokio::select! {
+                cmd = cmd_rx.recv() => {
+                    let ok = match cmd {
+                        Some(WriteCmd::SendRekey(public)) => {
+                            let sent = send_frame(&mut write_half, &mut send_cipher, FrameType::Rekey, &public).await;
+                            rekey_in_flight = sent.is_ok();
+                            sent.is_ok()
+                        }
+                        Some(WriteCmd::RespondAndSwap { our_new_public, new_send_key }) => {
+                            let sent = send_frame(&mut write_half, &mut send_cipher, FrameType::Rekey, &our_new_public).await;
+                            send_cipher = DirectionalCipher::new(new_send_key);
+                            sent.is_ok()
+                        }
+                        Some(WriteCmd::SwapSendKey(new_send_key)) => {
+                            send_cipher = DirectionalCipher::new(new_send_key);
+                            rekey_in_flight = false;
+                            true
+                        }
+                        None => true,
+                    };
+                    if !ok {
+                        break;
+                    }
+                }
+                plaintext = rx.recv(), if !rekey_in_flight => {
+                    match plaintext {
+                        Some(plaintext) => {
+                            if send_frame(&mut write_half, &mut send_cipher, FrameType::Data, &plaintext).await.is_err() {
+                                break;
+                            }
+                            let _ = sent_tx.send(());
+                        }
+                        None => break,
+                    }
+                }
+            }
+        }
+    });
 
-        let plaintext = xor_crypt(&ciphertext, &mut lcg);
-        let plaintext_str = String::from_utf8_lossy(&plaintext);
-        println!("[SERVER] {}", plaintext_str);
+    let mut rekey_state = RekeyState::Idle;
+    let mut messages_since_rekey: u64 = 0;
+    let mut rekey_timer = tokio::time::interval(REKEY_AFTER_TIME);
+    rekey_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    rekey_timer.tick().await; // first tick fires immediately
 
-        print!("[YOU] ");
-        io::stdout().flush()?;
-        input.clear();
-        io::stdin().read_line(&mut input)?;
-        let input = input.trim();
-        if input.is_empty() {
-            continue;
+    let result = loop {
+        tokio::select! {
+            _ = sent_rx.recv() => {
+                messages_since_rekey += 1;
+                if messages_since_rekey >= REKEY_AFTER_MESSAGES {
+                    messages_since_rekey = 0;
+                    if let Err(e) = start_rekey(&mut rekey_state, &cmd_tx).await {
+                        break Err(e);
+                    }
+                }
+            }
+            _ = rekey_timer.tick() => {
+                messages_since_rekey = 0;
+                if let Err(e) = start_rekey(&mut rekey_state, &cmd_tx).await {
+                    break Err(e);
+                }
+            }
+            frame = recv_frame(&mut read_half, &mut recv_cipher) => {
+                match frame {
+                    Ok((FrameType::Data, plaintext)) => {
+                        println!("[{}] {}", addr, String::from_utf8_lossy(&plaintext));
+                        // Snapshot the senders and drop the lock before sending.
+                        // `try_send` never waits: if a backpressured or rekeying
+                        // peer's channel is full, that peer misses this message
+                        // instead of stalling this client's loop.
+                        let peers: Vec<_> = roster
+                            .lock()
+                            .await
+                            .iter()
+                            .filter(|(peer_addr, _)| **peer_addr != addr)
+                            .map(|(_, peer_tx)| peer_tx.clone())
+                            .collect();
+                        for peer_tx in &peers {
+                            let _ = peer_tx.try_send(plaintext.clone());
+                        }
+                    }
+                    Ok((FrameType::Rekey, body)) => {
+                        if let Err(e) =
+                            handle_rekey_frame(&body, &mut rekey_state, &mut recv_cipher, true, &cmd_tx).await
+                        {
+                            break Err(e);
+                        }
+                    }
+                    Ok((FrameType::Close, _)) => break Ok(()),
+                    Err(e) => break Err(e),
+                }
+            }
         }
+    };
+
+    write_task.abort();
+    result
+}
+
+async fn run_server(transport: Box<dyn AsyncTransport>, port: u16) -> io::Result<()> {
+    let bind_addr = format!("0.0.0.0:{}", port);
+    let mut listener = transport.bind(&bind_addr).await?;
+    println!("[SERVER] Listening on {}", bind_addr);
 
-        let ciphertext = xor_crypt(input.as_bytes(), &mut lcg);
-        writer.write_all(&(ciphertext.len() as u32).to_be_bytes()).await?;
-        writer.write_all(&ciphertext).await?;
+    let roster: Roster = Arc::new(Mutex::new(HashMap::new()));
+
+    loop {
+        let pending = match listener.accept().await {
+            Ok(pending) => pending,
+            Err(e) => {
+                eprintln!("[SERVER] accept error: {}", e);
+                // A transient per-connection error shouldn't bring the hub
+                // down, but a persistent one (e.g. fd exhaustion) would
+                // otherwise spin this loop at 100% CPU; back off briefly.
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let roster = roster.clone();
+        tokio::spawn(async move {
+            let (stream, addr) = match pending.finish().await {
+                Ok(pair) => pair,
+                Err(e) => {
+                    eprintln!("[SERVER] connection handshake failed: {}", e);
+                    return;
+                }
+            };
+            println!("[SERVER] Client connected from {}", addr);
+
+            if let Err(e) = handle_client(stream, addr.clone(), roster.clone()).await {
+                eprintln!("[SERVER] session with {} ended: {}", addr, e);
+            }
+            roster.lock().await.remove(&addr);
+            println!("[SERVER] Client {} disconnected", addr);
+        });
     }
 }
 
+async fn run_client(transport: Box<dyn AsyncTransport>, addr: String) -> io::Result<()> {
+    let mut stream = transport.connect(&addr).await?;
+    println!("[CLIENT] Connected to server");
+
+    let crypto = handshake(&mut stream, false).await?;
+    run_chat_session(stream, crypto, false, "SERVER").await
+}
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
     let args = Args::parse();
     match args.command {
-        Command::Server { port } => run_server(port).await,
-        Command::Client { addr } => run_client(addr).await,
+        Command::Server { port, transport } => run_server(transport.build(), port).await,
+        Command::Client { addr, transport } => run_client(transport.build(), addr).await,
     }
-}
\ No newline at end of file
+}